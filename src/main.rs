@@ -3,11 +3,32 @@
 
 use {
     std::{
-        collections::BTreeMap,
+        collections::{
+            BTreeMap,
+            HashSet,
+        },
         ffi::OsString,
-        path::PathBuf,
+        io::Read as _,
+        path::{
+            Path,
+            PathBuf,
+        },
     },
     bytesize::ByteSize,
+    async_compression::tokio::{
+        bufread::{
+            BzDecoder,
+            GzipDecoder,
+            XzDecoder,
+            ZstdDecoder,
+        },
+        write::{
+            BzEncoder,
+            GzipEncoder,
+            XzEncoder,
+            ZstdEncoder,
+        },
+    },
     chrono::prelude::*,
     futures::stream::TryStreamExt as _,
     itertools::Itertools as _,
@@ -16,8 +37,15 @@ use {
         System,
     },
     tokio::{
+        io::{
+            AsyncWriteExt as _,
+            BufReader,
+        },
         pin,
-        process::Command,
+        process::{
+            Command,
+            Stdio,
+        },
     },
     wheel::{
         fs,
@@ -27,16 +55,96 @@ use {
 };
 
 const UNCOMPRESSED_FILENAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S.sql";
-const COMPRESSED_FILENAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S.sql.gz";
+/// Filename format for per-database backups, i.e. tar archives bundling one `pg_dump` per database plus a manifest,
+/// as opposed to the monolithic `pg_dumpall` output named per [`UNCOMPRESSED_FILENAME_FORMAT`].
+const TAR_FILENAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S.tar";
+/// Window size used for `xz`'s LZMA2 dictionary when none is given on the command line. A larger window shrinks SQL
+/// dumps noticeably at the cost of more RAM during compression/decompression, which is an acceptable tradeoff for
+/// archival backups.
+const DEFAULT_XZ_DICT_SIZE_MIB: u64 = 64;
+
+/// A compression algorithm that can be applied to a finished backup file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// All algorithms pgbackuproll knows how to produce or recognize, in no particular order.
+    const ALL: [Self; 4] = [Self::Gzip, Self::Zstd, Self::Xz, Self::Bzip2];
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bz2",
+        }
+    }
+
+    /// The external binary used to compress an already-written backup file in place.
+    fn command(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bzip2",
+        }
+    }
+
+    /// The filename format produced when a backup named after `base` (e.g. [`UNCOMPRESSED_FILENAME_FORMAT`] or
+    /// [`TAR_FILENAME_FORMAT`]) gets this compression applied.
+    fn filename_format(&self, base: &str) -> String {
+        format!("{base}.{}", self.extension())
+    }
+
+    /// Builds the command line used to compress `path` in place, replacing it with a file carrying this
+    /// algorithm's extension.
+    fn compress_command(&self, path: &Path, xz_dict_size: u64) -> Command {
+        let mut command = Command::new(self.command());
+        match self {
+            Self::Gzip | Self::Bzip2 => { command.arg(path); }
+            Self::Zstd => { command.arg("--rm").arg(path); }
+            Self::Xz => { command.arg(format!("--lzma2=preset=9,dict={xz_dict_size}MiB")).arg(path); }
+        }
+        command
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
     #[error(transparent)] ChronoParse(#[from] chrono::format::ParseError),
     #[error(transparent)] Io(#[from] std::io::Error),
+    #[error(transparent)] Join(#[from] tokio::task::JoinError),
     #[error(transparent)] Wheel(#[from] wheel::Error),
     #[error(transparent)] Xdg(#[from] xdg::BaseDirectoriesError),
     #[error("backup directory not found, create at /usr/local/share/pgbackuproll")]
     BackupDir,
+    #[error("one or more backups are corrupt")]
+    CorruptBackups,
+    #[error("--database is required to restore from a per-database backup")]
+    DatabaseRequired,
+    #[error("--database is only supported when restoring a per-database backup")]
+    DatabaseUnsupported,
+    #[error("--dbname is required for a deep verification")]
+    DbnameRequired,
+    #[error("--deep is only supported for --per-database backups")]
+    DeepVerifyUnsupported,
+    #[error("no backups found in backup directory")]
+    NoBackups,
+    #[error("backup does not contain a dump of database {0}")]
+    NoSuchDatabase(String),
+    #[error("no backup found with timestamp {0}")]
+    NoSuchBackup(DateTime<Utc>),
+    #[error("pg_dump exited with {0}")]
+    PgDump(std::process::ExitStatus),
+    #[error("pg_dumpall exited with {0}")]
+    PgDumpAll(std::process::ExitStatus),
+    #[error("psql exited with {0}")]
+    Psql(std::process::ExitStatus),
     #[error("failed to check file system stats at backup directory")]
     NoMount,
     #[error("non-UTF-8 filename")]
@@ -53,23 +161,39 @@ fn backup_path() -> Result<PathBuf, Error> {
     BaseDirectories::new()?.find_data_file("pgbackuproll").ok_or(Error::BackupDir)
 }
 
-/// Deletes the backup file that's closest to other backup files. In case of a tie, the oldest backup is deleted.
-///
-/// If only one backup file exists, it's not deleted and `false` is returned.
-async fn delete_one(verbose: bool) -> Result<bool, Error> {
-    let dir = backup_path()?;
+/// Parses the timestamp out of a backup filename, trying the uncompressed `.sql`/`.tar` formats followed by every
+/// known compressed `<fmt>.<ext>` variant of either.
+fn parse_backup_timestamp(filename: &str) -> Result<DateTime<Utc>, Error> {
+    let mut result = Utc.datetime_from_str(filename, UNCOMPRESSED_FILENAME_FORMAT)
+        .or_else(|_| Utc.datetime_from_str(filename, TAR_FILENAME_FORMAT));
+    for base in [UNCOMPRESSED_FILENAME_FORMAT, TAR_FILENAME_FORMAT] {
+        for compression in Compression::ALL {
+            if result.is_ok() { break }
+            result = Utc.datetime_from_str(filename, &compression.filename_format(base));
+        }
+    }
+    Ok(result?)
+}
+
+/// Reads the backup directory and maps each backup's parsed timestamp to its filename.
+async fn collect_backups(dir: &Path) -> Result<BTreeMap<DateTime<Utc>, String>, Error> {
     let mut timestamps = BTreeMap::default();
     pin! {
-        let entries = fs::read_dir(&dir);
+        let entries = fs::read_dir(dir);
     }
     while let Some(entry) = entries.try_next().await? {
         let filename = entry.file_name().into_string()?;
-        timestamps.insert(
-            Utc.datetime_from_str(&filename, UNCOMPRESSED_FILENAME_FORMAT)
-                .or_else(|_| Utc.datetime_from_str(&filename, COMPRESSED_FILENAME_FORMAT))?,
-            filename,
-        );
+        timestamps.insert(parse_backup_timestamp(&filename)?, filename);
     }
+    Ok(timestamps)
+}
+
+/// Deletes the backup file that's closest to other backup files. In case of a tie, the oldest backup is deleted.
+///
+/// If only one backup file exists, it's not deleted and `false` is returned.
+async fn delete_one(verbose: bool) -> Result<bool, Error> {
+    let dir = backup_path()?;
+    let timestamps = collect_backups(&dir).await?;
     let filename = match timestamps.len() {
         0 | 1 => return Ok(false),
         2 => timestamps.into_values().next().unwrap(),
@@ -86,6 +210,65 @@ async fn delete_one(verbose: bool) -> Result<bool, Error> {
     Ok(true)
 }
 
+/// A grandfather-father-son retention policy: keep this many of the most recent hourly/daily/weekly/monthly
+/// backups around, deleting the backups outside them first. Once only retained backups are left and more room is
+/// still needed, `make_room` falls back to `delete_one`'s neighbor-distance heuristic, which may delete retained ones.
+#[derive(Debug, Clone, Copy, Default)]
+struct GfsPolicy {
+    hourly: u32,
+    daily: u32,
+    weekly: u32,
+    monthly: u32,
+}
+
+impl GfsPolicy {
+    fn is_enabled(&self) -> bool {
+        self.hourly > 0 || self.daily > 0 || self.weekly > 0 || self.monthly > 0
+    }
+
+    /// Buckets `timestamps` by hour, day, ISO week and month and, walking newest to oldest, retains the first
+    /// backup seen in each of the most recent buckets allotted to that granularity. Returns every backup not
+    /// retained by any rule, oldest first.
+    fn deletion_candidates(&self, timestamps: &BTreeMap<DateTime<Utc>, String>) -> Vec<DateTime<Utc>> {
+        let mut retained = HashSet::new();
+        let mut hour_buckets = HashSet::new();
+        let mut day_buckets = HashSet::new();
+        let mut week_buckets = HashSet::new();
+        let mut month_buckets = HashSet::new();
+        for &timestamp in timestamps.keys().rev() {
+            if hour_buckets.len() < self.hourly as usize && hour_buckets.insert((timestamp.date_naive(), timestamp.hour())) {
+                retained.insert(timestamp);
+            }
+            if day_buckets.len() < self.daily as usize && day_buckets.insert(timestamp.date_naive()) {
+                retained.insert(timestamp);
+            }
+            let week = timestamp.iso_week();
+            if week_buckets.len() < self.weekly as usize && week_buckets.insert((week.year(), week.week())) {
+                retained.insert(timestamp);
+            }
+            if month_buckets.len() < self.monthly as usize && month_buckets.insert((timestamp.year(), timestamp.month())) {
+                retained.insert(timestamp);
+            }
+        }
+        timestamps.keys().filter(|timestamp| !retained.contains(timestamp)).copied().collect()
+    }
+}
+
+/// Deletes the oldest backup not retained by `policy`. Returns `false` (deleting nothing) if `policy` is disabled
+/// or every backup is currently retained by it.
+async fn delete_one_gfs(verbose: bool, policy: GfsPolicy) -> Result<bool, Error> {
+    if !policy.is_enabled() { return Ok(false) }
+    let dir = backup_path()?;
+    let timestamps = collect_backups(&dir).await?;
+    let Some(timestamp) = policy.deletion_candidates(&timestamps).into_iter().next() else { return Ok(false) };
+    let filename = &timestamps[&timestamp];
+    if verbose {
+        println!("deleting {filename}");
+    }
+    fs::remove_file(dir.join(filename)).await?;
+    Ok(true)
+}
+
 async fn make_backup() -> Result<(), Error> {
     Command::new("pg_dumpall")
         .stdout(std::fs::File::create(backup_path()?.join(Utc::now().format(UNCOMPRESSED_FILENAME_FORMAT).to_string()))?)
@@ -94,12 +277,117 @@ async fn make_backup() -> Result<(), Error> {
     Ok(())
 }
 
+/// Lists every non-template, connectable database in the cluster.
+async fn list_databases() -> Result<Vec<String>, Error> {
+    let output = Command::new("psql")
+        .args(["--tuples-only", "--no-align", "--quiet", "--command", "SELECT datname FROM pg_database WHERE NOT datistemplate AND datallowconn ORDER BY datname"])
+        .output().await?;
+    if !output.status.success() { return Err(Error::Psql(output.status)) }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().filter(|line| !line.is_empty()).map(str::to_owned).collect())
+}
+
+/// Queries the `server_version` setting, recorded in the manifest of per-database backups.
+async fn server_version() -> Result<String, Error> {
+    let output = Command::new("psql")
+        .args(["--tuples-only", "--no-align", "--quiet", "--command", "SHOW server_version"])
+        .output().await?;
+    if !output.status.success() { return Err(Error::Psql(output.status)) }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Runs `pg_dump` once per database in `databases` and bundles the results into the tar archive at `path`,
+/// alongside a `manifest.toml` recording `server_version` and each database's dump timestamp. Each dump is spooled
+/// to a temp file and streamed into the tar entry from there, rather than collected into memory via
+/// `Command::output`, so a large database doesn't blow up host memory. Blocking, so it's meant to be driven through
+/// [`tokio::task::spawn_blocking`].
+fn write_databases_tar(path: &Path, databases: &[String], server_version: &str) -> Result<(), Error> {
+    let mut builder = tar::Builder::new(std::fs::File::create(path)?);
+    let mut manifest = format!("server_version = \"{server_version}\"\n\n[dumped_at]\n");
+    for database in databases {
+        let dumped_at = Utc::now();
+        let mut dump_file = tempfile::NamedTempFile::new()?;
+        let status = std::process::Command::new("pg_dump").arg(database).stdout(dump_file.reopen()?).status()?;
+        if !status.success() { return Err(Error::PgDump(status)) }
+        let mut header = tar::Header::new_gnu();
+        header.set_size(dump_file.as_file().metadata()?.len());
+        header.set_mode(0o644);
+        header.set_mtime(dumped_at.timestamp().max(0) as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, format!("{database}.sql"), dump_file.as_file_mut())?;
+        manifest.push_str(&format!("{database:?} = \"{}\"\n", dumped_at.to_rfc3339()));
+    }
+    let manifest = manifest.into_bytes();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, "manifest.toml", &*manifest)?;
+    builder.into_inner()?;
+    Ok(())
+}
+
+/// Alternative to [`make_backup`] that dumps each database individually (via `pg_dump`) instead of the whole
+/// cluster at once (via `pg_dumpall`), so that a single database can later be restored without replaying the
+/// others. The archive is left uncompressed, same as [`make_backup`]; `make_room` compresses it later if needed.
+async fn make_backup_per_database() -> Result<(), Error> {
+    let databases = list_databases().await?;
+    let server_version = server_version().await?;
+    let path = backup_path()?.join(Utc::now().format(TAR_FILENAME_FORMAT).to_string());
+    tokio::task::spawn_blocking(move || write_databases_tar(&path, &databases, &server_version)).await??;
+    Ok(())
+}
+
+/// Like [`make_backup`], but pipes `pg_dumpall`'s output through a streaming encoder so only the compressed
+/// artifact ever touches disk, rather than writing the full uncompressed dump first. This matters on volumes that
+/// don't have room for both copies at once.
+async fn make_backup_streaming(compression: Compression) -> Result<(), Error> {
+    let path = backup_path()?.join(Utc::now().format(&compression.filename_format(UNCOMPRESSED_FILENAME_FORMAT)).to_string());
+    let file = tokio::fs::File::create(&path).await?;
+    let mut child = Command::new("pg_dumpall")
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("child was spawned with piped stdout");
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = GzipEncoder::new(file);
+            tokio::io::copy(&mut stdout, &mut encoder).await?;
+            encoder.shutdown().await?;
+        }
+        Compression::Zstd => {
+            let mut encoder = ZstdEncoder::new(file);
+            tokio::io::copy(&mut stdout, &mut encoder).await?;
+            encoder.shutdown().await?;
+        }
+        Compression::Xz => {
+            // the streaming encoder only takes a quality preset, not a custom dictionary size; `--xz-dict-size`
+            // only applies to the non-streaming compression done by `make_room` through the external `xz` binary
+            let mut encoder = XzEncoder::new(file);
+            tokio::io::copy(&mut stdout, &mut encoder).await?;
+            encoder.shutdown().await?;
+        }
+        Compression::Bzip2 => {
+            let mut encoder = BzEncoder::new(file);
+            tokio::io::copy(&mut stdout, &mut encoder).await?;
+            encoder.shutdown().await?;
+        }
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        // the encoder above already wrote a well-formed footer onto the truncated dump, so the file would
+        // otherwise decompress cleanly and pass `verify` despite being garbage
+        fs::remove_file(&path).await?;
+        return Err(Error::PgDumpAll(status))
+    }
+    Ok(())
+}
+
 /// `amount` should be a number between 0 and 100. Backups will be deleted until:
 ///
 /// * at least `amount` gibibytes are free _and_ at least `amount` % of the disk is free (returns `Ok(true)`),
 /// * only one backup file is remaining (returns `Ok(false)`), or
 /// * an error occurs (returns `Err(_)`).
-async fn make_room(amount: u64, verbose: bool) -> Result<bool, Error> {
+async fn make_room(amount: u64, verbose: bool, compression: Compression, xz_dict_size: u64, gfs: GfsPolicy) -> Result<bool, Error> {
     let dir = backup_path()?;
     loop {
         let fs = dir.ancestors().map(|ancestor| System::new().mount_at(ancestor)).find_map(Result::ok).ok_or(Error::NoMount)?;
@@ -110,7 +398,8 @@ async fn make_room(amount: u64, verbose: bool) -> Result<bool, Error> {
             let mut smallest_uncompressed = None;
             while let Some(entry) = entries.try_next().await? {
                 let path = entry.path();
-                if path.extension().and_then(|ext| ext.to_str()) != Some("gz") {
+                let ext = path.extension().and_then(|ext| ext.to_str());
+                if !Compression::ALL.iter().any(|compression| Some(compression.extension()) == ext) {
                     // this works because the backups are regular files, not directories
                     let size = entry.metadata().await?.len();
                     if smallest_uncompressed.as_ref().map_or(true, |&(_, smallest_size)| size < smallest_size) {
@@ -120,32 +409,259 @@ async fn make_room(amount: u64, verbose: bool) -> Result<bool, Error> {
             }
             if let Some((path, size)) = smallest_uncompressed {
                 if ByteSize::b(size) < fs.avail {
-                    Command::new("gzip")
-                        .arg(path)
-                        .check("gzip").await?;
+                    compression.compress_command(&path, xz_dict_size).check(compression.command()).await?;
                     continue
                 }
             }
-            // not enough room to compress anything or no uncompressed backups left, delete backups to make room
-            if !delete_one(verbose).await? { return Ok(false) }
+            // not enough room to compress anything or no uncompressed backups left, delete backups to make room,
+            // preferring GFS retention candidates over the neighbor-distance heuristic
+            if !delete_one_gfs(verbose, gfs).await? && !delete_one(verbose).await? { return Ok(false) }
         } else {
             return Ok(true)
         }
     }
 }
 
+/// Reads `path`, decompressing it first if its extension names a known [`Compression`], and returns the decoded
+/// bytes.
+async fn read_decompressed(path: &Path) -> Result<Vec<u8>, Error> {
+    let compression = path.extension().and_then(|ext| ext.to_str()).and_then(|ext| Compression::ALL.into_iter().find(|compression| compression.extension() == ext));
+    let mut reader = BufReader::new(tokio::fs::File::open(path).await?);
+    let mut data = Vec::new();
+    match compression {
+        Some(Compression::Gzip) => { tokio::io::copy(&mut GzipDecoder::new(reader), &mut data).await?; }
+        Some(Compression::Zstd) => { tokio::io::copy(&mut ZstdDecoder::new(reader), &mut data).await?; }
+        Some(Compression::Xz) => { tokio::io::copy(&mut XzDecoder::new(reader), &mut data).await?; }
+        Some(Compression::Bzip2) => { tokio::io::copy(&mut BzDecoder::new(reader), &mut data).await?; }
+        None => { tokio::io::copy(&mut reader, &mut data).await?; }
+    }
+    Ok(data)
+}
+
+/// Whether `filename` names a per-database backup ([`TAR_FILENAME_FORMAT`]), ignoring any compression extension.
+fn is_tar_backup(filename: &str) -> bool {
+    let without_compression = Compression::ALL.into_iter().find_map(|compression| filename.strip_suffix(&format!(".{}", compression.extension()))).unwrap_or(filename);
+    without_compression.ends_with(".tar")
+}
+
+/// Extracts the `{database}.sql` entry from a decompressed per-database tar archive.
+fn extract_database_dump(tar: Vec<u8>, database: &str) -> Result<Vec<u8>, Error> {
+    let entry_name = format!("{database}.sql");
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tar));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(&entry_name) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            return Ok(data)
+        }
+    }
+    Err(Error::NoSuchDatabase(database.to_owned()))
+}
+
+/// Picks the backup matching `timestamp` (or the most recent one if `timestamp` is `"latest"`), transparently
+/// decompresses it if it carries a compression extension, and streams it into `psql`. If the backup is a
+/// per-database archive (see [`make_backup_per_database`]), `database` selects which database's dump to restore.
+async fn restore(timestamp: &str, database: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let dir = backup_path()?;
+    let mut timestamps = collect_backups(&dir).await?;
+    let filename = if timestamp == "latest" {
+        timestamps.into_values().next_back().ok_or(Error::NoBackups)?
+    } else {
+        let timestamp = Utc.datetime_from_str(timestamp, UNCOMPRESSED_FILENAME_FORMAT.trim_end_matches(".sql"))?;
+        timestamps.remove(&timestamp).ok_or(Error::NoSuchBackup(timestamp))?
+    };
+    let is_tar = is_tar_backup(&filename);
+    if is_tar && database.is_none() { return Err(Error::DatabaseRequired) }
+    if !is_tar && database.is_some() { return Err(Error::DatabaseUnsupported) }
+    let path = dir.join(&filename);
+    if verbose {
+        println!("restoring {filename}");
+    }
+    let data = read_decompressed(&path).await?;
+    let (data, database) = if is_tar {
+        let database = database.expect("checked above");
+        (tokio::task::spawn_blocking({
+            let database = database.to_owned();
+            move || extract_database_dump(data, &database)
+        }).await??, Some(database))
+    } else {
+        (data, None)
+    };
+    let mut psql = Command::new("psql");
+    if let Some(database) = database {
+        psql.arg("--dbname").arg(database);
+    }
+    let mut psql = psql.stdin(Stdio::piped()).spawn()?;
+    let mut stdin = psql.stdin.take().expect("child was spawned with piped stdin");
+    stdin.write_all(&data).await?;
+    drop(stdin);
+    let status = psql.wait().await?;
+    if !status.success() { return Err(Error::Psql(status)) }
+    Ok(())
+}
+
+/// Reads every entry of a decompressed per-database tar archive to the end, confirming it isn't truncated. If
+/// `deep` is set, also collects the contents of each `{database}.sql` entry (skipping `manifest.toml`) for the
+/// caller to replay through `psql`; unlike the monolithic `pg_dumpall` format, a single-database `pg_dump` doesn't
+/// embed role creation or `\connect` directives, so replaying it against a throwaway database is actually safe.
+fn verify_tar(data: Vec<u8>, deep: bool) -> Result<Vec<Vec<u8>>, Error> {
+    let mut archive = tar::Archive::new(std::io::Cursor::new(data));
+    let mut dumps = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if deep && entry.path()?.extension().and_then(|ext| ext.to_str()) == Some("sql") {
+            let mut dump = Vec::new();
+            entry.read_to_end(&mut dump)?;
+            dumps.push(dump);
+        } else {
+            std::io::copy(&mut entry, &mut std::io::sink())?;
+        }
+    }
+    Ok(dumps)
+}
+
+/// Feeds `dump` into `psql --set ON_ERROR_STOP=on` against the throwaway database `dbname`, catching SQL the
+/// decompression/tar checks above would otherwise silently let through.
+async fn replay_into_throwaway(dump: &[u8], dbname: &str) -> Result<(), Error> {
+    let mut psql = Command::new("psql")
+        .args(["--set", "ON_ERROR_STOP=on", "--quiet", "--dbname"]).arg(dbname)
+        .args(["--file", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = psql.stdin.take().expect("child was spawned with piped stdin");
+    stdin.write_all(dump).await?;
+    drop(stdin);
+    let status = psql.wait().await?;
+    if !status.success() { return Err(Error::Psql(status)) }
+    Ok(())
+}
+
+/// Confirms `path` decompresses cleanly (and, for a per-database archive, that the tar itself isn't truncated).
+/// If `deep` is set, each dump is also replayed into the throwaway database `dbname` via
+/// [`replay_into_throwaway`]. Deep verification is only supported for per-database archives: a monolithic
+/// `pg_dumpall` backup starts with `CREATE ROLE` statements and embeds `\connect` directives that switch the
+/// active database mid-script, so there is no safe way to confine replaying it to a single throwaway database.
+async fn verify_one(path: &Path, filename: &str, deep: bool, dbname: Option<&str>) -> Result<(), Error> {
+    let data = read_decompressed(path).await?;
+    if is_tar_backup(filename) {
+        let dumps = tokio::task::spawn_blocking(move || verify_tar(data, deep)).await??;
+        if deep {
+            let dbname = dbname.ok_or(Error::DbnameRequired)?;
+            for dump in dumps {
+                replay_into_throwaway(&dump, dbname).await?;
+            }
+        }
+        return Ok(())
+    }
+    if deep { return Err(Error::DeepVerifyUnsupported) }
+    Ok(())
+}
+
+/// For every backup in the backup directory, confirms it decompresses cleanly (and, with `deep` set, that each
+/// per-database dump replays cleanly into the throwaway database `dbname`) and prints a line reporting its status.
+/// Returns [`Error::CorruptBackups`] if any backup failed verification, so this can run from cron as a guard
+/// against silently bad archives before `make_room` prunes the last good one.
+async fn verify(deep: bool, dbname: Option<&str>) -> Result<(), Error> {
+    let dir = backup_path()?;
+    let timestamps = collect_backups(&dir).await?;
+    let mut any_corrupt = false;
+    for filename in timestamps.into_values() {
+        match verify_one(&dir.join(&filename), &filename, deep, dbname).await {
+            Ok(()) => println!("{filename}: ok"),
+            Err(error) => {
+                println!("{filename}: corrupt ({error})");
+                any_corrupt = true;
+            }
+        }
+    }
+    if any_corrupt { return Err(Error::CorruptBackups) }
+    Ok(())
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Decompress a backup (if necessary) and pipe it into `psql`.
+    Restore {
+        /// Which backup to restore, by its timestamp (`%Y-%m-%d_%H-%M-%S`), or `latest` for the most recent one.
+        #[clap(default_value = "latest")]
+        timestamp: String,
+        /// Which database's dump to extract and restore. Required when restoring a per-database backup, and
+        /// rejected otherwise since a monolithic backup has no single database to extract.
+        #[clap(long)]
+        database: Option<String>,
+    },
+    /// Check every backup for corruption, reporting per-backup status and exiting non-zero if any are corrupt.
+    Verify {
+        /// Also replay each dump into a throwaway database via `psql --set ON_ERROR_STOP=on`, not just confirm it
+        /// decompresses cleanly. Only supported for `--per-database` backups: a monolithic `pg_dumpall` backup
+        /// can't be safely confined to a single throwaway database (see `DeepVerifyUnsupported`).
+        #[clap(long)]
+        deep: bool,
+        /// The throwaway database `--deep` replays per-database backups into. Required when `--deep` is set.
+        #[clap(long)]
+        dbname: Option<String>,
+    },
+}
+
 #[derive(clap::Parser)]
 #[clap(version)]
 struct Args {
     #[clap(short, long)]
     verbose: bool,
+    /// Compression algorithm used when a backup needs to be compressed to free up space.
+    #[clap(long, value_enum, default_value = "gzip")]
+    compression: Compression,
+    /// LZMA2 dictionary/window size, in mebibytes, used when `--compression xz` is selected. Not applied with
+    /// `--stream-compress`, whose xz encoder uses its default dictionary size.
+    #[clap(long, default_value_t = DEFAULT_XZ_DICT_SIZE_MIB)]
+    xz_dict_size: u64,
+    /// Compress the dump as it's being written instead of writing it uncompressed and compressing it later, so
+    /// peak disk usage is just the compressed size.
+    #[clap(long)]
+    stream_compress: bool,
+    /// Number of most recent hourly backups to retain. Other backups are deleted first; the neighbor-distance
+    /// heuristic only deletes retained backups once no others are left.
+    #[clap(long, default_value_t = 0)]
+    keep_hourly: u32,
+    /// Number of most recent daily backups to retain. Other backups are deleted first; the neighbor-distance
+    /// heuristic only deletes retained backups once no others are left.
+    #[clap(long, default_value_t = 0)]
+    keep_daily: u32,
+    /// Number of most recent weekly backups to retain. Other backups are deleted first; the neighbor-distance
+    /// heuristic only deletes retained backups once no others are left.
+    #[clap(long, default_value_t = 0)]
+    keep_weekly: u32,
+    /// Number of most recent monthly backups to retain. Other backups are deleted first; the neighbor-distance
+    /// heuristic only deletes retained backups once no others are left.
+    #[clap(long, default_value_t = 0)]
+    keep_monthly: u32,
+    /// Dump each database individually into a tar archive instead of the whole cluster at once via `pg_dumpall`,
+    /// so a single database can later be restored without replaying the others.
+    #[clap(long)]
+    per_database: bool,
+    /// If omitted, pgbackuproll creates a new backup and prunes old ones as usual.
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
 }
 
 #[wheel::main(debug)]
-async fn main(Args { verbose }: Args) -> Result<(), Error> {
-    if make_room(10, verbose).await? {
-        make_backup().await?;
-        make_room(10, verbose).await?;
+async fn main(Args { verbose, compression, xz_dict_size, stream_compress, keep_hourly, keep_daily, keep_weekly, keep_monthly, per_database, command }: Args) -> Result<(), Error> {
+    let gfs = GfsPolicy { hourly: keep_hourly, daily: keep_daily, weekly: keep_weekly, monthly: keep_monthly };
+    match command {
+        Some(Subcommand::Restore { timestamp, database }) => restore(&timestamp, database.as_deref(), verbose).await?,
+        Some(Subcommand::Verify { deep, dbname }) => verify(deep, dbname.as_deref()).await?,
+        None => if make_room(10, verbose, compression, xz_dict_size, gfs).await? {
+            if per_database {
+                make_backup_per_database().await?;
+            } else if stream_compress {
+                make_backup_streaming(compression).await?;
+            } else {
+                make_backup().await?;
+            }
+            make_room(10, verbose, compression, xz_dict_size, gfs).await?;
+        },
     }
     Ok(())
 }